@@ -0,0 +1,344 @@
+//! Builtin procedures, installed into the default top-level environment.
+
+use cell_gc::{GcHeapSession, GcLeaf};
+use value::{BuiltinFnPtr, InternedString, Pair, Value};
+use value::Value::*;
+use vm::{EnvironmentRef, Trampoline};
+
+type BuiltinFn = for<'b> fn(&mut GcHeapSession<'b>, Vec<Value<'b>>) -> Result<Trampoline<'b>, String>;
+
+pub fn define_builtins<'h>(env: EnvironmentRef<'h>) {
+    def(&env, "set-car!", set_car);
+    def(&env, "set-cdr!", set_cdr);
+    def(&env, "car", car);
+    def(&env, "cdr", cdr);
+    def(&env, "cons", cons);
+    def(&env, "append", append);
+    def(&env, "pair?", pair_q);
+    def(&env, "eqv?", eqv_q);
+    def(&env, "not", not);
+    def(&env, "+", add);
+    def(&env, "-", sub);
+    def(&env, "*", mul);
+    def(&env, "=", num_eq);
+    def(&env, "string-ref", string_ref);
+    def(&env, "string-set!", string_set);
+    def(&env, "string-length", string_length);
+    def(&env, "substring", substring);
+    def(&env, "string->symbol", string_to_symbol);
+    def(&env, "symbol->string", symbol_to_string);
+    def(&env, "list->string", list_to_string);
+    def(&env, "error", error);
+}
+
+/// The whitelist of primitives `compile::fold_constants` is allowed to
+/// evaluate at compile time: pure, deterministic builtins whose result
+/// depends only on their arguments (a builtin with side effects like
+/// `set-car!` must never be added here). The single place that whitelist is
+/// defined; shared by `try_fold` (fold a call away once its arguments are
+/// already constant) and `is_unshadowed_primitive` (confirm `name` still
+/// means this builtin before folding a call to it away).
+fn builtin_for(name: &str) -> Option<BuiltinFn> {
+    match name {
+        "+" => Some(add),
+        "-" => Some(sub),
+        "*" => Some(mul),
+        "=" => Some(num_eq),
+        "not" => Some(not),
+        "car" => Some(car),
+        "cdr" => Some(cdr),
+        "eqv?" => Some(eqv_q),
+        "pair?" => Some(pair_q),
+        _ => None,
+    }
+}
+
+/// Given a builtin's name and already-constant arguments, apply it
+/// immediately and hand back the resulting `Value`, or `None` if `name`
+/// isn't one of the whitelisted builtins above.
+pub(crate) fn try_fold<'h>(
+    hs: &mut GcHeapSession<'h>,
+    name: &str,
+    args: Vec<Value<'h>>,
+) -> Option<Result<Value<'h>, String>> {
+    let f = builtin_for(name)?;
+    Some(f(hs, args).map(|trampoline| match trampoline {
+        Trampoline::Value(v) => v,
+        Trampoline::TailCall { .. } => {
+            unreachable!("every builtin in try_fold's whitelist returns Trampoline::Value")
+        }
+    }))
+}
+
+/// True if `env`'s current top-level binding for `name` is still exactly
+/// the builtin `try_fold` would run for it -- i.e. nothing has shadowed or
+/// redefined `name` since the interpreter started. `language::run`
+/// compiles (and, at `OptLevel::Basic`, folds) each top-level form against
+/// a persistent `env`, evaluating every form as it goes, so a `(define + ...)`
+/// in an earlier form has already taken effect in `env` by the time a later
+/// form naming `+` is folded; `compile::fold_app` must check this before
+/// folding such a call away, or the redefinition would be silently bypassed.
+pub(crate) fn is_unshadowed_primitive<'h>(env: &EnvironmentRef<'h>, name: InternedString) -> bool {
+    let expected = match name.with_str(builtin_for) {
+        Some(f) => f,
+        None => return false,
+    };
+    match env.get(name) {
+        Ok(Builtin(f)) => f.unwrap().0 as usize == expected as usize,
+        _ => false,
+    }
+}
+
+fn def<'h>(
+    env: &EnvironmentRef<'h>,
+    name: &str,
+    f: for<'b> fn(&mut GcHeapSession<'b>, Vec<Value<'b>>) -> Result<Trampoline<'b>, String>,
+) {
+    env.push(InternedString::get(name), Builtin(GcLeaf::new(BuiltinFnPtr(f))));
+}
+
+fn set_car<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 2 {
+        return Err("set-car!: exactly 2 arguments required".to_string());
+    }
+    let new_car = args.pop().unwrap();
+    let pair = args.pop().unwrap();
+    match pair {
+        Cons(p) => {
+            p.set_car(new_car);
+            Ok(Trampoline::Value(Nil))
+        }
+        _ => Err("set-car!: pair required".to_string()),
+    }
+}
+
+fn set_cdr<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 2 {
+        return Err("set-cdr!: exactly 2 arguments required".to_string());
+    }
+    let new_cdr = args.pop().unwrap();
+    let pair = args.pop().unwrap();
+    match pair {
+        Cons(p) => {
+            p.set_cdr(new_cdr);
+            Ok(Trampoline::Value(Nil))
+        }
+        _ => Err("set-cdr!: pair required".to_string()),
+    }
+}
+
+fn car<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("car: exactly 1 argument required".to_string());
+    }
+    match args.pop().unwrap() {
+        Cons(p) => Ok(Trampoline::Value(p.car())),
+        _ => Err("car: pair required".to_string()),
+    }
+}
+
+fn cdr<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("cdr: exactly 1 argument required".to_string());
+    }
+    match args.pop().unwrap() {
+        Cons(p) => Ok(Trampoline::Value(p.cdr())),
+        _ => Err("cdr: pair required".to_string()),
+    }
+}
+
+fn cons<'h>(hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 2 {
+        return Err("cons: exactly 2 arguments required".to_string());
+    }
+    let cdr = args.pop().unwrap();
+    let car = args.pop().unwrap();
+    Ok(Trampoline::Value(Cons(hs.alloc(Pair { car, cdr }))))
+}
+
+// A 2-argument `append`, which is all `quasiquote`'s `unquote-splicing`
+// lowering needs: splice one list in before the rest of the structure.
+fn append<'h>(hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 2 {
+        return Err("append: exactly 2 arguments required".to_string());
+    }
+    let tail = args.pop().unwrap();
+    let list = args.pop().unwrap();
+    let mut items = vec![];
+    for item in list {
+        items.push(item?);
+    }
+    let mut result = tail;
+    for item in items.into_iter().rev() {
+        result = Cons(hs.alloc(Pair { car: item, cdr: result }));
+    }
+    Ok(Trampoline::Value(result))
+}
+
+fn pair_q<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("pair?: exactly 1 argument required".to_string());
+    }
+    Ok(Trampoline::Value(Bool(args.pop().unwrap().is_pair())))
+}
+
+// `eqv?` rather than `Value`'s derived `PartialEq` (`eq?`-ish in spirit, but
+// see `Value::num_eq`'s doc comment): two numbers compare equal if they
+// denote the same mathematical integer, regardless of whether one is a
+// fixnum `Int` and the other a promoted `BigInt`.
+fn eqv_q<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 2 {
+        return Err("eqv?: exactly 2 arguments required".to_string());
+    }
+    let b = args.pop().unwrap();
+    let a = args.pop().unwrap();
+    let eq = if a.is_number() && b.is_number() {
+        a.num_eq(&b, "eqv?")?
+    } else {
+        a == b
+    };
+    Ok(Trampoline::Value(Bool(eq)))
+}
+
+fn not<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("not: exactly 1 argument required".to_string());
+    }
+    Ok(Trampoline::Value(Bool(!args.pop().unwrap().to_bool())))
+}
+
+// The arithmetic builtins below all take the fast path on `i32`s and, on
+// overflow, fall back to arbitrary-precision arithmetic; `Value::checked_add`
+// et al. demote the result back to a fixnum `Int` when it fits, so `Int` and
+// `BigInt` stay indistinguishable as far as Scheme code is concerned.
+
+fn add<'h>(_hs: &mut GcHeapSession<'h>, args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    let mut acc = Int(0);
+    for arg in args {
+        acc = acc.checked_add(arg, "+")?;
+    }
+    Ok(Trampoline::Value(acc))
+}
+
+fn sub<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.is_empty() {
+        return Err("-: at least 1 argument required".to_string());
+    }
+    if args.len() == 1 {
+        return Ok(Trampoline::Value(Int(0).checked_sub(args.pop().unwrap(), "-")?));
+    }
+    let mut args = args.into_iter();
+    let mut acc = args.next().unwrap();
+    for arg in args {
+        acc = acc.checked_sub(arg, "-")?;
+    }
+    Ok(Trampoline::Value(acc))
+}
+
+fn mul<'h>(_hs: &mut GcHeapSession<'h>, args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    let mut acc = Int(1);
+    for arg in args {
+        acc = acc.checked_mul(arg, "*")?;
+    }
+    Ok(Trampoline::Value(acc))
+}
+
+fn num_eq<'h>(_hs: &mut GcHeapSession<'h>, args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() < 2 {
+        return Err("=: at least 2 arguments required".to_string());
+    }
+    for w in args.windows(2) {
+        if !w[0].num_eq(&w[1], "=")? {
+            return Ok(Trampoline::Value(Bool(false)));
+        }
+    }
+    Ok(Trampoline::Value(Bool(true)))
+}
+
+fn string_ref<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 2 {
+        return Err("string-ref: exactly 2 arguments required".to_string());
+    }
+    let index = args.pop().unwrap().as_index("string-ref")?;
+    let s = args.pop().unwrap().as_string("string-ref")?;
+    let c = s.chars()
+        .nth(index)
+        .ok_or_else(|| "string-ref: index out of range".to_string())?;
+    Ok(Trampoline::Value(Char(c)))
+}
+
+fn string_set<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 3 {
+        return Err("string-set!: exactly 3 arguments required".to_string());
+    }
+    let c = args.pop().unwrap().as_char("string-set!")?;
+    let index = args.pop().unwrap().as_index("string-set!")?;
+    let s = args.pop().unwrap().as_string_obj("string-set!")?;
+    if index >= s.len() {
+        return Err("string-set!: index out of range".to_string());
+    }
+    s.set(index, c);
+    Ok(Trampoline::Value(Nil))
+}
+
+fn string_length<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("string-length: exactly 1 argument required".to_string());
+    }
+    let s = args.pop().unwrap().as_string("string-length")?;
+    Ok(Trampoline::Value(Int(s.chars().count() as i32)))
+}
+
+fn substring<'h>(hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 3 {
+        return Err("substring: exactly 3 arguments required".to_string());
+    }
+    let end = args.pop().unwrap().as_index("substring")?;
+    let start = args.pop().unwrap().as_index("substring")?;
+    let s = args.pop().unwrap().as_string("substring")?;
+    if start > end || end > s.chars().count() {
+        return Err("substring: index out of range".to_string());
+    }
+    let chars: Vec<char> = s.chars().skip(start).take(end - start).collect();
+    Ok(Trampoline::Value(StringObj(hs.alloc(chars))))
+}
+
+fn string_to_symbol<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("string->symbol: exactly 1 argument required".to_string());
+    }
+    let s = args.pop().unwrap().as_string("string->symbol")?;
+    Ok(Trampoline::Value(Symbol(GcLeaf::new(InternedString::get(&s)))))
+}
+
+fn symbol_to_string<'h>(hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("symbol->string: exactly 1 argument required".to_string());
+    }
+    let name = args.pop().unwrap().as_symbol("symbol->string")?;
+    let chars: Vec<char> = name.as_str().chars().collect();
+    Ok(Trampoline::Value(StringObj(hs.alloc(chars))))
+}
+
+fn list_to_string<'h>(hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("list->string: exactly 1 argument required".to_string());
+    }
+    let list = args.pop().unwrap();
+    let mut chars = vec![];
+    for item in list {
+        chars.push(item?.as_char("list->string")?);
+    }
+    Ok(Trampoline::Value(StringObj(hs.alloc(chars))))
+}
+
+// Unconditionally fails with `message` -- used by `compile::compile_match`
+// to compile `match`'s "no clause matched" fallthrough into a real runtime
+// error instead of a counterfeit string value a caller could mistake for
+// legitimate output.
+fn error<'h>(_hs: &mut GcHeapSession<'h>, mut args: Vec<Value<'h>>) -> Result<Trampoline<'h>, String> {
+    if args.len() != 1 {
+        return Err("error: exactly 1 argument required".to_string());
+    }
+    Err(args.pop().unwrap().as_string("error")?)
+}
@@ -0,0 +1,108 @@
+//! A `Language` is a pluggable front end over the shared trampoline
+//! evaluator in `vm`: anything that knows how to read source text into
+//! forms and compile a form into an `Expr`. `vm::eval` hard-wires
+//! "`compile::compile_toplevel`, then run" for one already-read form;
+//! routing a front end through the `run` driver below instead means a new surface syntax
+//! can be plugged in without reimplementing evaluation, and tooling like
+//! the REPL in `repl` can observe each stage the same way no matter which
+//! `Language` produced it.
+
+use cell_gc::GcHeapSession;
+use compile::{self, Expr, OptLevel};
+use macros::MacroEnv;
+use parse;
+use value::Value;
+use vm::{self, EnvironmentRef, Trampoline};
+
+pub trait Language {
+    /// Read `source` into the forms this language's reader produces.
+    fn read<'h>(&self, hs: &mut GcHeapSession<'h>, source: &str) -> Result<Vec<Value<'h>>, String>;
+
+    /// Compile one form (as produced by `read`) down to an `Expr` at the
+    /// given `opt` level. `menv` is shared across every form read from the
+    /// same `source`, so a macro one form defines is in scope for the
+    /// forms that follow it. `env` is the runtime environment this form
+    /// will be evaluated in, already reflecting every earlier form's
+    /// side effects (see `fold_constants`'s doc comment) -- it's needed so
+    /// folding at `OptLevel::Basic` can tell a still-original primitive
+    /// from one an earlier top-level form already redefined.
+    fn compile<'h>(
+        &self,
+        hs: &mut GcHeapSession<'h>,
+        menv: &mut MacroEnv<'h>,
+        form: Value<'h>,
+        env: &EnvironmentRef<'h>,
+        opt: OptLevel,
+    ) -> Result<Expr<'h>, String>;
+}
+
+/// The language this crate has always spoken: s-expressions compiled by
+/// `compile::compile_toplevel`.
+pub struct Scheme;
+
+impl Language for Scheme {
+    fn read<'h>(&self, hs: &mut GcHeapSession<'h>, source: &str) -> Result<Vec<Value<'h>>, String> {
+        parse::parse(hs, source)
+    }
+
+    fn compile<'h>(
+        &self,
+        hs: &mut GcHeapSession<'h>,
+        menv: &mut MacroEnv<'h>,
+        form: Value<'h>,
+        env: &EnvironmentRef<'h>,
+        opt: OptLevel,
+    ) -> Result<Expr<'h>, String> {
+        let expr = compile::compile_toplevel(hs, menv, form)?;
+        Ok(match opt {
+            OptLevel::None => expr,
+            OptLevel::Basic => compile::fold_constants(hs, expr, env),
+        })
+    }
+}
+
+/// Observes each stage of running forms through a `Language`, so a driver
+/// like the REPL can dump or single-step the pipeline. Every hook defaults
+/// to doing nothing, so implementors only need to override the stages they
+/// care about.
+pub trait StageHooks<'h> {
+    fn parsed(&mut self, _form: &Value<'h>) {}
+    fn compiled(&mut self, _expr: &Expr<'h>) {}
+    fn tail_call(&mut self, _call: &Trampoline<'h>) {}
+    fn evaluated(&mut self, _value: &Value<'h>) {}
+}
+
+/// The hooks used when nobody wants to observe the pipeline.
+pub struct NoHooks;
+impl<'h> StageHooks<'h> for NoHooks {}
+
+/// Read every form out of `source` with `lang`, compiling (at the given
+/// `opt` level) and evaluating each one in turn in `env`, reporting each
+/// pipeline stage (parsed form, compiled `Expr`, trampoline bounce, final
+/// value) to `hooks`. Returns the value of the last form, as `vm::eval`
+/// does for a single form.
+pub fn run<'h, L: Language, H: StageHooks<'h>>(
+    hs: &mut GcHeapSession<'h>,
+    lang: &L,
+    source: &str,
+    env: EnvironmentRef<'h>,
+    opt: OptLevel,
+    hooks: &mut H,
+) -> Result<Value<'h>, String> {
+    let forms = lang.read(hs, source)?;
+    let mut menv = MacroEnv::new();
+    let mut result = Value::Nil;
+    for form in forms {
+        hooks.parsed(&form);
+        let expr = lang.compile(hs, &mut menv, form, &env, opt)?;
+        hooks.compiled(&expr);
+        // CPS-convert after `hooks.compiled` fires, so a verbose REPL still
+        // dumps the plain compiled `Expr` a user would recognize, not the
+        // gensym-heavy CPS form evaluation actually runs.
+        let cps_expr = compile::cps_toplevel(hs, expr);
+        let tail = vm::eval_to_tail_call(hs, cps_expr, env.clone())?;
+        result = tail.eval_traced(hs, &mut |call| hooks.tail_call(call))?;
+        hooks.evaluated(&result);
+    }
+    Ok(result)
+}
@@ -0,0 +1,517 @@
+//! `define-syntax`/`let-syntax` and the `syntax-rules` pattern/template
+//! matcher they're built on.
+//!
+//! This runs as a macro-expansion pass over the raw `Value` s-expression
+//! tree, strictly before `compile::compile_expr`/`compile::compile_body`
+//! ever see a macro invocation: whenever one of them finds a form whose
+//! head names a macro in scope, it calls `expand_step` to get the next
+//! form and keeps expanding -- re-checking the (possibly new) head symbol
+//! each time -- until it stops naming a macro, then compiles what's left.
+//!
+//! Scope: `match_pattern` supports a single `...` per pattern list (a
+//! subpattern under `...` may contain its own), which covers the large majority of `syntax-rules`
+//! macros people write by hand. Hygiene is enforced only for identifiers a
+//! template itself binds, via a literal `lambda`/`let`/`let*`/`letrec`/
+//! `letrec*` written in the template: those get renamed to fresh
+//! `InternedString::gensym()` names so they can't capture (or be captured
+//! by) identifiers the caller passed in. Other template identifiers --
+//! the ones that refer to bindings outside the macro, like `if` or `+` --
+//! are left alone, since renaming them correctly would require resolving
+//! against the macro's definition environment, which doesn't exist yet at
+//! this pre-compile expansion stage.
+
+use cell_gc::{GcHeapSession, GcLeaf};
+use errors::Result;
+use std::collections::{HashMap, HashSet};
+use value::{InternedString, Pair, Value};
+use value::Value::*;
+
+/// One `syntax-rules` macro: the literal identifiers it was defined with,
+/// plus an ordered list of `(pattern template)` rules, tried in turn until
+/// one matches.
+#[derive(Clone)]
+pub struct SyntaxRules<'h> {
+    literals: HashSet<InternedString>,
+    rules: Vec<(Value<'h>, Value<'h>)>,
+}
+
+/// A chain of macro scopes, innermost first, that `define-syntax` and
+/// `let-syntax` push bindings into. This lives purely at compile time --
+/// unlike `vm::Environment`, it never touches the GC heap -- but is
+/// threaded through `compile::compile_toplevel`/`compile_body`/
+/// `compile_expr` the same way an interpreter threads a lexical
+/// environment.
+pub struct MacroEnv<'h> {
+    scopes: Vec<HashMap<InternedString, SyntaxRules<'h>>>,
+}
+
+impl<'h> MacroEnv<'h> {
+    pub fn new() -> MacroEnv<'h> {
+        MacroEnv {
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    /// Push a fresh, empty scope, for the duration of a `let-syntax` body.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Pop the innermost scope, undoing the bindings made since the
+    /// matching `push_scope`.
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Bind `name` to `rules` in the innermost scope.
+    pub fn define(&mut self, name: InternedString, rules: SyntaxRules<'h>) {
+        self.scopes
+            .last_mut()
+            .expect("MacroEnv always has a scope")
+            .insert(name, rules);
+    }
+
+    /// Look up `name`, searching from the innermost scope outward.
+    pub fn lookup(&self, name: InternedString) -> Option<&SyntaxRules<'h>> {
+        self.scopes.iter().rev().filter_map(|scope| scope.get(&name)).next()
+    }
+}
+
+fn head_is<'h>(form: &Value<'h>, name: &str) -> bool {
+    if let Cons(ref pair) = *form {
+        if let Symbol(ref op) = pair.car() {
+            return op.is(name);
+        }
+    }
+    false
+}
+
+/// True if `form` is a `(define-syntax NAME TRANSFORMER)` form.
+pub fn is_syntax_definition<'h>(form: &Value<'h>) -> bool {
+    head_is(form, "define-syntax")
+}
+
+/// Parse and register a `(define-syntax NAME (syntax-rules ...))` form.
+pub fn parse_syntax_definition<'h>(menv: &mut MacroEnv<'h>, form: Value<'h>) -> Result<()> {
+    let (_define_syntax, tail) = form.as_pair("internal error")?;
+    let (name_v, tail) = tail.as_pair("define-syntax: name required")?;
+    let name = name_v.as_symbol("define-syntax: name must be a symbol")?;
+    let (transformer, rest) = tail.as_pair("define-syntax: transformer required")?;
+    if !rest.is_nil() {
+        return Err("define-syntax: too many arguments".into());
+    }
+    let rules = parse_transformer(transformer)?;
+    menv.define(name, rules);
+    Ok(())
+}
+
+/// Parse a `(syntax-rules (LITERAL ...) (PATTERN TEMPLATE) ...)` transformer.
+pub fn parse_transformer<'h>(transformer: Value<'h>) -> Result<SyntaxRules<'h>> {
+    if !head_is(&transformer, "syntax-rules") {
+        return Err("define-syntax: only (syntax-rules ...) transformers are supported".into());
+    }
+    let (_keyword, tail) = transformer.as_pair("internal error")?;
+    let (literals_list, rules_list) = tail.as_pair("syntax-rules: literals list required")?;
+
+    let mut literals = HashSet::new();
+    for lit_result in literals_list {
+        let lit = lit_result?;
+        literals.insert(lit.as_symbol("syntax-rules: literals must be symbols")?);
+    }
+
+    let mut rules = vec![];
+    for rule_result in rules_list {
+        let rule = rule_result?;
+        let (pattern, rest) = rule.as_pair("syntax-rules: rule must be (pattern template)")?;
+        let (template, rest) = rest.as_pair("syntax-rules: rule is missing a template")?;
+        if !rest.is_nil() {
+            return Err("syntax-rules: rule has too many parts".into());
+        }
+        rules.push((pattern, template));
+    }
+
+    Ok(SyntaxRules { literals, rules })
+}
+
+/// If `form`'s head symbol names a macro in `menv`, expand it one step and
+/// return the result. Returns `None` when `form` isn't a macro invocation,
+/// so callers can loop `while let Some(expanded) = expand_step(...)`.
+pub fn expand_step<'h>(
+    hs: &mut GcHeapSession<'h>,
+    menv: &MacroEnv<'h>,
+    form: &Value<'h>,
+) -> Result<Option<Value<'h>>> {
+    let head = match *form {
+        Cons(ref pair) => match pair.car() {
+            Symbol(ref s) => s.clone().unwrap(),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let rules = match menv.lookup(head) {
+        Some(rules) => rules.clone(),
+        None => return Ok(None),
+    };
+
+    for (pattern, template) in &rules.rules {
+        // The leading element of the pattern is the macro's keyword
+        // position (conventionally `_`); it's matched positionally but
+        // never bound, so skip straight to the cdrs.
+        if let (Cons(ref pp), Cons(ref fp)) = (pattern.clone(), form.clone()) {
+            let mut bindings = HashMap::new();
+            if match_pattern(pp.cdr(), fp.cdr(), &rules.literals, &mut bindings) {
+                let pattern_vars: HashSet<InternedString> = bindings.keys().cloned().collect();
+                let mut bound = HashSet::new();
+                collect_template_bound_names(template, &pattern_vars, &mut bound);
+                let mut renames = HashMap::new();
+                return Ok(Some(instantiate(hs, template, &bindings, &bound, &mut renames)?));
+            }
+        }
+    }
+    Err(format!("no syntax-rules pattern for ({} ...) matches", head.as_str()))
+}
+
+/// What a pattern variable is bound to: either a single matched form, or
+/// (when the variable appeared under a `...`) one binding per repetition.
+#[derive(Clone)]
+enum Binding<'h> {
+    One(Value<'h>),
+    Many(Vec<Binding<'h>>),
+}
+
+fn list_len<'h>(v: &Value<'h>) -> usize {
+    let mut n = 0;
+    let mut cur = v.clone();
+    while let Cons(p) = cur {
+        n += 1;
+        cur = p.cdr();
+    }
+    n
+}
+
+fn pattern_vars<'h>(pattern: &Value<'h>, literals: &HashSet<InternedString>) -> HashSet<InternedString> {
+    let mut vars = HashSet::new();
+    collect_pattern_vars(pattern, literals, &mut vars);
+    vars
+}
+
+fn collect_pattern_vars<'h>(
+    pattern: &Value<'h>,
+    literals: &HashSet<InternedString>,
+    out: &mut HashSet<InternedString>,
+) {
+    match *pattern {
+        Symbol(ref s) => {
+            let name = s.clone().unwrap();
+            if !s.is("_") && !s.is("...") && !literals.contains(&name) {
+                out.insert(name);
+            }
+        }
+        Cons(ref p) => {
+            collect_pattern_vars(&p.car(), literals, out);
+            collect_pattern_vars(&p.cdr(), literals, out);
+        }
+        _ => {}
+    }
+}
+
+fn match_pattern<'h>(
+    pattern: Value<'h>,
+    input: Value<'h>,
+    literals: &HashSet<InternedString>,
+    bindings: &mut HashMap<InternedString, Binding<'h>>,
+) -> bool {
+    match pattern {
+        Symbol(ref s) if s.is("_") => true,
+        Symbol(ref s) => {
+            let name = s.clone().unwrap();
+            if literals.contains(&name) {
+                match input {
+                    Symbol(ref is) => is.clone().unwrap() == name,
+                    _ => false,
+                }
+            } else {
+                bindings.insert(name, Binding::One(input));
+                true
+            }
+        }
+        Cons(pp) => {
+            let phead = pp.car();
+            let ptail = pp.cdr();
+            if let Cons(ref pt) = ptail {
+                if let Symbol(ref s) = pt.car() {
+                    if s.is("...") {
+                        return match_ellipsis(phead, pt.cdr(), input, literals, bindings);
+                    }
+                }
+            }
+            match input {
+                Cons(ip) => {
+                    match_pattern(phead, ip.car(), literals, bindings)
+                        && match_pattern(ptail, ip.cdr(), literals, bindings)
+                }
+                _ => false,
+            }
+        }
+        Nil => input.is_nil(),
+        other => other == input,
+    }
+}
+
+fn match_ellipsis<'h>(
+    sub_pattern: Value<'h>,
+    after: Value<'h>,
+    input: Value<'h>,
+    literals: &HashSet<InternedString>,
+    bindings: &mut HashMap<InternedString, Binding<'h>>,
+) -> bool {
+    let input_len = list_len(&input);
+    let after_len = list_len(&after);
+    if input_len < after_len {
+        return false;
+    }
+    let take = input_len - after_len;
+
+    let vars = pattern_vars(&sub_pattern, literals);
+    let mut collected: HashMap<InternedString, Vec<Binding<'h>>> =
+        vars.iter().cloned().map(|v| (v, vec![])).collect();
+
+    let mut remaining = input;
+    for _ in 0..take {
+        let (item, rest) = match remaining.as_pair("internal: ellipsis match") {
+            Ok(pair) => pair,
+            Err(_) => return false,
+        };
+        let mut sub_bindings = HashMap::new();
+        if !match_pattern(sub_pattern.clone(), item, literals, &mut sub_bindings) {
+            return false;
+        }
+        for v in &vars {
+            if let Some(b) = sub_bindings.remove(v) {
+                collected.get_mut(v).unwrap().push(b);
+            }
+        }
+        remaining = rest;
+    }
+
+    for (name, values) in collected {
+        bindings.insert(name, Binding::Many(values));
+    }
+
+    match_pattern(after, remaining, literals, bindings)
+}
+
+// Hygiene: identifiers a template binds itself ///////////////////////////
+
+fn collect_template_bound_names<'h>(
+    template: &Value<'h>,
+    pattern_vars: &HashSet<InternedString>,
+    out: &mut HashSet<InternedString>,
+) {
+    let pair = match *template {
+        Cons(ref p) => p,
+        _ => return,
+    };
+
+    if let Symbol(ref head) = pair.car() {
+        let head_str = head.as_str();
+        let is_binder = head_str == "lambda" || head_str == "let" || head_str == "let*"
+            || head_str == "letrec" || head_str == "letrec*";
+        if is_binder {
+            if let Ok((formals, body)) = pair.cdr().as_pair("") {
+                if head_str == "lambda" {
+                    collect_names_from_formals(&formals, pattern_vars, out);
+                } else {
+                    collect_names_from_bindings(&formals, pattern_vars, out);
+                }
+                walk_list_for_bound_names(&body, pattern_vars, out);
+                return;
+            }
+        }
+    }
+
+    collect_template_bound_names(&pair.car(), pattern_vars, out);
+    collect_template_bound_names(&pair.cdr(), pattern_vars, out);
+}
+
+fn collect_names_from_formals<'h>(
+    formals: &Value<'h>,
+    pattern_vars: &HashSet<InternedString>,
+    out: &mut HashSet<InternedString>,
+) {
+    let mut cur = formals.clone();
+    loop {
+        match cur {
+            Cons(p) => {
+                if let Symbol(ref s) = p.car() {
+                    add_bound_name(s.clone().unwrap(), pattern_vars, out);
+                }
+                cur = p.cdr();
+            }
+            Symbol(ref s) => {
+                add_bound_name(s.clone().unwrap(), pattern_vars, out);
+                break;
+            }
+            _ => break,
+        }
+    }
+}
+
+fn collect_names_from_bindings<'h>(
+    bindings_list: &Value<'h>,
+    pattern_vars: &HashSet<InternedString>,
+    out: &mut HashSet<InternedString>,
+) {
+    let mut cur = bindings_list.clone();
+    while let Cons(p) = cur {
+        if let Ok((name_v, rest)) = p.car().as_pair("") {
+            if let Symbol(ref s) = name_v {
+                add_bound_name(s.clone().unwrap(), pattern_vars, out);
+            }
+            if let Ok((value_expr, _)) = rest.as_pair("") {
+                collect_template_bound_names(&value_expr, pattern_vars, out);
+            }
+        }
+        cur = p.cdr();
+    }
+}
+
+fn walk_list_for_bound_names<'h>(
+    body: &Value<'h>,
+    pattern_vars: &HashSet<InternedString>,
+    out: &mut HashSet<InternedString>,
+) {
+    let mut cur = body.clone();
+    while let Cons(p) = cur {
+        collect_template_bound_names(&p.car(), pattern_vars, out);
+        cur = p.cdr();
+    }
+}
+
+fn add_bound_name<'h>(
+    name: InternedString,
+    pattern_vars: &HashSet<InternedString>,
+    out: &mut HashSet<InternedString>,
+) {
+    if !pattern_vars.contains(&name) {
+        out.insert(name);
+    }
+}
+
+// Template instantiation //////////////////////////////////////////////////
+
+fn ellipsis_count<'h>(
+    sub_template: &Value<'h>,
+    bindings: &HashMap<InternedString, Binding<'h>>,
+) -> Result<usize> {
+    let mut count = None;
+    collect_ellipsis_count(sub_template, bindings, &mut count)?;
+    count.ok_or_else(|| {
+        "syntax-rules: '...' in template but no pattern variable under '...' is used there".into()
+    })
+}
+
+fn collect_ellipsis_count<'h>(
+    template: &Value<'h>,
+    bindings: &HashMap<InternedString, Binding<'h>>,
+    count: &mut Option<usize>,
+) -> Result<()> {
+    match *template {
+        Symbol(ref s) => {
+            let name = s.clone().unwrap();
+            if let Some(&Binding::Many(ref vs)) = bindings.get(&name) {
+                match *count {
+                    None => *count = Some(vs.len()),
+                    Some(n) if n == vs.len() => {}
+                    Some(_) => return Err("syntax-rules: mismatched '...' lengths in template".into()),
+                }
+            }
+        }
+        Cons(ref p) => {
+            collect_ellipsis_count(&p.car(), bindings, count)?;
+            collect_ellipsis_count(&p.cdr(), bindings, count)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn nth_bindings_view<'h>(
+    bindings: &HashMap<InternedString, Binding<'h>>,
+    i: usize,
+) -> HashMap<InternedString, Binding<'h>> {
+    bindings
+        .iter()
+        .map(|(k, v)| {
+            let v = match *v {
+                Binding::Many(ref vs) => vs[i].clone(),
+                Binding::One(ref val) => Binding::One(val.clone()),
+            };
+            (*k, v)
+        })
+        .collect()
+}
+
+fn build_list<'h>(hs: &mut GcHeapSession<'h>, items: Vec<Value<'h>>, tail: Value<'h>) -> Value<'h> {
+    let mut result = tail;
+    for item in items.into_iter().rev() {
+        result = Cons(hs.alloc(Pair {
+            car: item,
+            cdr: result,
+        }));
+    }
+    result
+}
+
+fn instantiate<'h>(
+    hs: &mut GcHeapSession<'h>,
+    template: &Value<'h>,
+    bindings: &HashMap<InternedString, Binding<'h>>,
+    renamed: &HashSet<InternedString>,
+    renames: &mut HashMap<InternedString, InternedString>,
+) -> Result<Value<'h>> {
+    match *template {
+        Symbol(ref s) => {
+            let name = s.clone().unwrap();
+            match bindings.get(&name) {
+                Some(&Binding::One(ref v)) => Ok(v.clone()),
+                Some(&Binding::Many(_)) => {
+                    Err("syntax-rules: pattern variable used without a following '...'".into())
+                }
+                None => {
+                    if renamed.contains(&name) {
+                        let fresh = *renames.entry(name).or_insert_with(InternedString::gensym);
+                        Ok(Symbol(GcLeaf::new(fresh)))
+                    } else {
+                        Ok(template.clone())
+                    }
+                }
+            }
+        }
+        Cons(ref p) => {
+            let head = p.car();
+            let tail = p.cdr();
+            if let Cons(ref tp) = tail {
+                if let Symbol(ref s) = tp.car() {
+                    if s.is("...") {
+                        let count = ellipsis_count(&head, bindings)?;
+                        let mut items = Vec::with_capacity(count);
+                        for i in 0..count {
+                            let nth_bindings = nth_bindings_view(bindings, i);
+                            items.push(instantiate(hs, &head, &nth_bindings, renamed, renames)?);
+                        }
+                        let rest = instantiate(hs, &tp.cdr(), bindings, renamed, renames)?;
+                        return Ok(build_list(hs, items, rest));
+                    }
+                }
+            }
+            let new_head = instantiate(hs, &head, bindings, renamed, renames)?;
+            let new_tail = instantiate(hs, &tail, bindings, renamed, renames)?;
+            Ok(Cons(hs.alloc(Pair {
+                car: new_head,
+                cdr: new_tail,
+            })))
+        }
+        ref other => Ok(other.clone()),
+    }
+}
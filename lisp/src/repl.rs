@@ -0,0 +1,69 @@
+//! An interactive read-eval-print loop built on the `Language` driver in
+//! `language`. In verbose mode it dumps every pipeline stage — the parsed
+//! form, the compiled `Expr`, and each `Trampoline::TailCall` as the
+//! trampoline bounces — so users debugging tail-call behavior can watch the
+//! evaluator unwind the stack one call at a time.
+
+use cell_gc::GcHeapSession;
+use compile::{Expr, OptLevel};
+use language::{run, NoHooks, Scheme, StageHooks};
+use std::io::{self, BufRead, Write};
+use value::Value;
+use vm::{Environment, Trampoline};
+
+struct Verbose;
+
+impl<'h> StageHooks<'h> for Verbose {
+    fn parsed(&mut self, form: &Value<'h>) {
+        println!("  parsed:    {}", form);
+    }
+
+    fn compiled(&mut self, expr: &Expr<'h>) {
+        println!("  compiled:  {:?}", expr);
+    }
+
+    fn tail_call(&mut self, call: &Trampoline<'h>) {
+        if let Trampoline::TailCall { ref func, ref args } = *call {
+            let args = args.iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<String>>()
+                .join(" ");
+            println!("  tail call: {} {}", func, args);
+        }
+    }
+
+    fn evaluated(&mut self, value: &Value<'h>) {
+        println!("  =>         {}", value);
+    }
+}
+
+/// Run an interactive REPL on stdin/stdout. With `verbose` set, every
+/// pipeline stage is printed as it happens; otherwise only the value of
+/// the last form on each line is printed.
+pub fn repl<'h>(hs: &mut GcHeapSession<'h>, verbose: bool) {
+    let env = Environment::default_env(hs);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = if verbose {
+            run(hs, &Scheme, &line, env.clone(), OptLevel::Basic, &mut Verbose)
+        } else {
+            run(hs, &Scheme, &line, env.clone(), OptLevel::Basic, &mut NoHooks)
+        };
+        match result {
+            Ok(ref v) if !verbose => println!("{}", v),
+            Ok(_) => {}
+            Err(err) => println!("error: {}", err),
+        }
+    }
+}
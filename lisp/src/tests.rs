@@ -0,0 +1,323 @@
+// Included directly into the `vm` module by `#[cfg(test)] include!("tests.rs");`
+// above, so everything `vm` already has in scope (including the `lisp!`
+// macro) is available here without importing it again.
+
+#[test]
+fn cps_trampoline_survives_deep_non_tail_recursion() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `(letrec ((sum (lambda (n) (if (= n 0) 0 (+ n (sum (- n 1)))))))
+        //    (sum 100000))`
+        //
+        // The `(+ n (sum (- n 1)))` recursive call is *not* in tail
+        // position, so the ordinary evaluator would recurse natively one
+        // Rust stack frame per level of `sum` and could blow the stack at
+        // this depth. Running the CPS form of this program instead turns
+        // every one of those calls into a tail call that `Trampoline::eval`
+        // bounces through without growing the native stack.
+        let program = lisp! {
+            (letrec ((sum (lambda (n)
+                            (if (= n 0)
+                                0
+                                (+ n (sum (- n 1)))))))
+                (sum 100000)),
+            hs
+        };
+
+        let mut menv = macros::MacroEnv::new();
+        let compiled = compile::compile_toplevel(hs, &mut menv, program).unwrap();
+        let cps_compiled = compile::cps_toplevel(hs, compiled);
+        let result = eval_compiled(hs, cps_compiled, env).unwrap();
+
+        // 100000 * 100001 / 2 = 5000050000, past `i32::MAX`, so the sum
+        // comes back as a promoted `BigInt`.
+        assert_eq!(result.to_string(), "5000050000");
+    });
+}
+
+#[test]
+fn cps_seq_runs_effects_in_order_and_returns_last_value() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // A 2+-expression body compiles to `Expr::Seq` and goes through
+        // `cps_seq`. If `cps_seq` discarded the last expression's value (or
+        // mis-threaded the continuation), this would return the wrong
+        // answer or crash with "apply: not a function".
+        //
+        // `(letrec ((f (lambda (n) (set! n (+ n 1)) (set! n (+ n 1)) n))) (f 0))`,
+        // built by hand around `lisp!` since `set!` isn't a single Rust
+        // token tree.
+        let inc = lisp! { (+ n 1), hs };
+        let set_n = scheme_list(hs, vec![sym("set!"), sym("n"), inc]);
+        let params = lisp! { (n), hs };
+        let f_lambda = scheme_list(hs, vec![sym("lambda"), params, set_n.clone(), set_n, sym("n")]);
+        let binding = scheme_list(hs, vec![sym("f"), f_lambda]);
+        let bindings = scheme_list(hs, vec![binding]);
+        let call = lisp! { (f 0), hs };
+        let program = scheme_list(hs, vec![sym("letrec"), bindings, call]);
+
+        let mut menv = macros::MacroEnv::new();
+        let compiled = compile::compile_toplevel(hs, &mut menv, program).unwrap();
+        let cps_compiled = compile::cps_toplevel(hs, compiled);
+        let result = eval_compiled(hs, cps_compiled, env).unwrap();
+
+        assert_eq!(result, Int(2));
+    });
+}
+
+#[test]
+fn match_binds_nested_list_patterns_and_falls_through_on_mismatch() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `(a b)` is a nested `Cons` pattern: matching it requires the
+        // `car`/`cdr` bindings `compile_pattern` extracts for the outer
+        // pair to still be in scope when testing the *inner* pair's car
+        // and cdr, not just when evaluating the clause body.
+        let program = lisp! {
+            (match (cons 1 (cons 2 ()))
+                ((a b) (+ a b))
+                (_ 99)),
+            hs
+        };
+        let result = eval(hs, program, env.clone()).unwrap();
+        assert_eq!(result, Int(3));
+
+        // A scrutinee that isn't a pair at all must fall through to the
+        // next clause rather than erroring out of an eager `car`/`cdr`.
+        let program2 = lisp! {
+            (match 5
+                ((a b) a)
+                (_ 99)),
+            hs
+        };
+        let result2 = eval(hs, program2, env).unwrap();
+        assert_eq!(result2, Int(99));
+    });
+}
+
+#[test]
+fn match_with_no_matching_clause_raises_an_error() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // Falling off the end of every clause must raise a real error --
+        // not return a string a caller could mistake for a legitimate
+        // match result.
+        let program = lisp! {
+            (match 5
+                ((a) a)),
+            hs
+        };
+        let err = eval(hs, program, env).unwrap_err();
+        assert_eq!(err, "match: no matching clause");
+    });
+}
+
+#[test]
+fn fold_constants_skips_folding_a_primitive_redefined_by_an_earlier_top_level_form() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+        let mut menv = macros::MacroEnv::new();
+
+        // Redefine `+` at the top level -- the way a REPL line does -- and
+        // run it, so `env` reflects the redefinition the same way it
+        // already would by the time `language::run` folds the *next*
+        // top-level form.
+        let redefine = lisp! { (define + (lambda (a b) 999)), hs };
+        let compiled = compile::compile_toplevel(hs, &mut menv, redefine).unwrap();
+        let folded = compile::fold_constants(hs, compiled, &env);
+        let cps = compile::cps_toplevel(hs, folded);
+        eval_compiled(hs, cps, env.clone()).unwrap();
+
+        // If `fold_app` folded this call using the *original* `+` builtin
+        // instead of checking `env`'s now-redefined binding, this would
+        // wrongly become `Expr::Con(Int(3))` at compile time and bypass
+        // the redefinition entirely.
+        let call = lisp! { (+ 1 2), hs };
+        let compiled = compile::compile_toplevel(hs, &mut menv, call).unwrap();
+        let folded = compile::fold_constants(hs, compiled, &env);
+        let cps = compile::cps_toplevel(hs, folded);
+        let result = eval_compiled(hs, cps, env).unwrap();
+
+        assert_eq!(result, Int(999));
+    });
+}
+
+#[test]
+fn set_car_and_set_cdr_mutate_in_place_and_cyclic_pairs_print_with_datum_labels() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `(let ((p (cons 1 2))) (set-car! p 10) (set-cdr! p 20) p)`;
+        // `set-car!`/`set-cdr!` aren't single Rust token trees, so their
+        // calls are built by hand around `lisp!`.
+        let bindings = lisp! { ((p (cons 1 2))), hs };
+        let set_car_call = scheme_list(hs, vec![sym("set-car!"), sym("p"), Int(10)]);
+        let set_cdr_call = scheme_list(hs, vec![sym("set-cdr!"), sym("p"), Int(20)]);
+        let program = scheme_list(hs, vec![sym("let"), bindings, set_car_call, set_cdr_call, sym("p")]);
+        let result = eval(hs, program, env.clone()).unwrap();
+        assert_eq!(result.to_string(), "(10 . 20)");
+
+        // A pair whose cdr is set back to itself is cyclic; `Display` must
+        // terminate and use a `#n=`/`#n#` datum label rather than looping
+        // forever walking `cdr`s.
+        // `(let ((p (cons 1 2))) (set-cdr! p p) p)`
+        let cyclic_bindings = lisp! { ((p (cons 1 2))), hs };
+        let set_cdr_self = scheme_list(hs, vec![sym("set-cdr!"), sym("p"), sym("p")]);
+        let cyclic_program = scheme_list(hs, vec![sym("let"), cyclic_bindings, set_cdr_self, sym("p")]);
+        let cyclic = eval(hs, cyclic_program, env).unwrap();
+        assert_eq!(cyclic.to_string(), "#0=(1 . #0#)");
+    });
+}
+
+#[test]
+fn arithmetic_promotes_to_bigint_on_overflow_and_demotes_back_when_it_fits() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `i32::MAX + 1` can't fit in a fixnum `Int`, so `+` must promote to
+        // a `BigInt` instead of wrapping or erroring.
+        let program = lisp! { (+ 2147483647 1), hs };
+        let result = eval(hs, program, env.clone()).unwrap();
+        assert_eq!(result.to_string(), "2147483648");
+
+        // Subtracting back down below the fixnum boundary must demote the
+        // `BigInt` result back to a fixnum `Int`; `Value`'s derived
+        // `PartialEq` tells an `Int` from a `BigInt`, so this checks the
+        // representation, not just the numeric value.
+        let demote_program = lisp! { (- (+ 2147483647 1) 1), hs };
+        let demoted = eval(hs, demote_program, env).unwrap();
+        assert_eq!(demoted, Int(2147483647));
+    });
+}
+
+// `lisp!` builds its `Cons` cells straight from Rust tokens, and `#\x`
+// character literals aren't valid Rust syntax, so a char-bearing list has
+// to be built by hand instead of through the macro. The same goes for
+// symbols like `set-car!` or `let*`, which Rust splits into several tokens.
+fn scheme_list<'h>(hs: &mut GcHeapSession<'h>, items: Vec<Value<'h>>) -> Value<'h> {
+    let mut acc = Nil;
+    for item in items.into_iter().rev() {
+        acc = Cons(hs.alloc(Pair { car: item, cdr: acc }));
+    }
+    acc
+}
+
+fn sym<'h>(name: &str) -> Value<'h> {
+    Symbol(GcLeaf::new(InternedString::get(name)))
+}
+
+#[test]
+fn string_set_mutates_a_gc_managed_string_in_place() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `(list->string '(#\a #\b #\c))` builds a mutable `StringObj`,
+        // unlike a quoted string literal's `ImmString`; `string-set!`
+        // mutates it in place, so reading it back after the mutation must
+        // see the new character.
+        let chars = scheme_list(hs, vec![Char('a'), Char('b'), Char('c')]);
+        let quoted_chars = scheme_list(hs, vec![sym("quote"), chars]);
+        let make_string = scheme_list(hs, vec![sym("list->string"), quoted_chars]);
+        let s_var = sym("s");
+        let binding = scheme_list(hs, vec![s_var.clone(), make_string]);
+        let bindings = scheme_list(hs, vec![binding]);
+        let set_call = scheme_list(hs, vec![sym("string-set!"), s_var.clone(), Int(1), Char('Z')]);
+        let ref_call = scheme_list(hs, vec![sym("string-ref"), s_var, Int(1)]);
+        let program = scheme_list(hs, vec![sym("let"), bindings, set_call, ref_call]);
+
+        let result = eval(hs, program, env).unwrap();
+        assert_eq!(result, Char('Z'));
+    });
+}
+
+#[test]
+fn syntax_rules_macro_expands_an_ellipsis_pattern() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `(_ x ...)` must bind `x` to every argument and the template's
+        // trailing `x ...` must splice all of them back in, in order.
+        //
+        // `(let-syntax ((my-sum (syntax-rules () ((_ x ...) (+ x ...))))) (my-sum 1 2 3))`;
+        // the hyphenated names are built by hand around `lisp!`.
+        let rule = lisp! { ((_ x ...) (+ x ...)), hs };
+        let transformer = scheme_list(hs, vec![sym("syntax-rules"), Nil, rule]);
+        let binding = scheme_list(hs, vec![sym("my-sum"), transformer]);
+        let bindings = scheme_list(hs, vec![binding]);
+        let call = scheme_list(hs, vec![sym("my-sum"), Int(1), Int(2), Int(3)]);
+        let program = scheme_list(hs, vec![sym("let-syntax"), bindings, call]);
+        let result = eval(hs, program, env).unwrap();
+        assert_eq!(result, Int(6));
+    });
+}
+
+#[test]
+fn quasiquote_splices_unquote_and_unquote_splicing() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `,(+ 1 1)` evaluates in place; `,@(cons 3 (cons 4 ()))` splices
+        // its list contents in rather than nesting it as a single element.
+        //
+        // `(quasiquote (1 (unquote (+ 1 1)) (unquote-splicing (cons 3 (cons 4 ())))))`;
+        // `unquote-splicing` is built by hand around `lisp!`.
+        let unquoted = lisp! { (unquote (+ 1 1)), hs };
+        let spliced_list = lisp! { (cons 3 (cons 4 ())), hs };
+        let splice = scheme_list(hs, vec![sym("unquote-splicing"), spliced_list]);
+        let template = scheme_list(hs, vec![Int(1), unquoted, splice]);
+        let program = scheme_list(hs, vec![sym("quasiquote"), template]);
+        let result = eval(hs, program, env).unwrap();
+        assert_eq!(result.to_string(), "(1 2 3 4)");
+    });
+}
+
+#[test]
+fn derived_forms_desugar_to_the_expected_values() {
+    cell_gc::with_heap(|hs| {
+        let env = Environment::default_env(hs);
+
+        // `let*` sees each earlier binding while computing the next one,
+        // unlike plain `let`.
+        // `(let* ((a 1) (b (+ a 1))) (+ a b))`, with `let*` built by hand.
+        let let_star_bindings = lisp! { ((a 1) (b (+ a 1))), hs };
+        let let_star_body = lisp! { (+ a b), hs };
+        let let_star = scheme_list(hs, vec![sym("let*"), let_star_bindings, let_star_body]);
+        assert_eq!(eval(hs, let_star, env.clone()).unwrap(), Int(3));
+
+        // `cond` falls through non-matching clauses to the matching one.
+        // (`#t`/`#f` aren't valid single Rust token trees, so booleans here
+        // are produced via `=` rather than written as literals.)
+        let cond_form = lisp! {
+            (cond ((= 1 2) 1) ((= 1 1) 2) ((= 1 1) 3)),
+            hs
+        };
+        assert_eq!(eval(hs, cond_form, env.clone()).unwrap(), Int(2));
+
+        // `and`/`or` short-circuit and return the deciding value.
+        let and_form = lisp! { (and 1 (= 1 2) 2), hs };
+        assert_eq!(eval(hs, and_form, env.clone()).unwrap(), Bool(false));
+        let or_form = lisp! { (or (= 1 2) (= 1 2) 5), hs };
+        assert_eq!(eval(hs, or_form, env.clone()).unwrap(), Int(5));
+
+        // `when`/`unless` run their body only when the test has the
+        // expected truthiness, and evaluate to `nil` otherwise.
+        let when_form = lisp! { (when (= 1 1) 1 2 3), hs };
+        assert_eq!(eval(hs, when_form, env.clone()).unwrap(), Int(3));
+        let unless_form = lisp! { (unless (= 1 1) 1 2 3), hs };
+        assert_eq!(eval(hs, unless_form, env.clone()).unwrap(), Nil);
+
+        // Named `let` is a self-recursive binding usable as a loop.
+        let named_let = lisp! {
+            (let loop ((n 5) (acc 0))
+                (if (= n 0)
+                    acc
+                    (loop (- n 1) (+ acc n)))),
+            hs
+        };
+        assert_eq!(eval(hs, named_let, env).unwrap(), Int(15));
+    });
+}
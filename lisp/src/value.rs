@@ -1,10 +1,11 @@
 use cell_gc::{GcHeapSession, GcLeaf};
 use cell_gc::collections::VecRef;
 use compile;
-use std::borrow::Borrow;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::{Arc, Mutex};
-use std::collections::HashSet;
 use vm::{EnvironmentRef, Trampoline};
 
 #[derive(Debug, IntoHeap)]
@@ -18,12 +19,22 @@ pub enum Value<'h> {
     Nil,
     Bool(bool),
     Int(i32),
+    BigInt(GcLeaf<BigInt>),
+    Char(char),
     Symbol(GcLeaf<InternedString>),
     Lambda(PairRef<'h>),
     Code(compile::CodeRef<'h>),
     Builtin(GcLeaf<BuiltinFnPtr>),
     Cons(PairRef<'h>),
     Vector(VecRef<'h, Value<'h>>),
+    /// An immutable string, e.g. a string literal read from source. Doesn't
+    /// need to be traced by the collector, since its contents can never
+    /// change or point back into the GC heap.
+    ImmString(GcLeaf<Arc<str>>),
+    /// A mutable, growable string, backed by a GC-allocated buffer of
+    /// `char`s so that `string-set!` and growth are traced and reclaimed
+    /// the same way `Vector` already is.
+    StringObj(VecRef<'h, char>),
     Environment(EnvironmentRef<'h>),
 }
 
@@ -55,50 +66,242 @@ impl fmt::Debug for BuiltinFnPtr {
     }
 }
 
+// `set-car!`/`set-cdr!` mean a pair's `cdr` can point back at an ancestor
+// pair (or a vector can contain itself), so naive recursive printing would
+// never terminate. We print in two passes instead: first we walk the
+// reachable graph, keyed by each `PairRef`/`VecRef`'s own identity, counting
+// how many times each one is reached; anything reached more than once
+// (including an object that reaches itself) is "shared". Then we print,
+// assigning each shared object a R7RS datum label (`#n=`) the first time we
+// visit it and emitting a back-reference (`#n#`) — without recursing further
+// — on every later visit. Because shared-ness is known before we start
+// printing, and recursion always stops at an already-labeled visit, this
+// terminates even on cyclic structure.
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum SharedObj<'h> {
+    Pair(PairRef<'h>),
+    Vector(VecRef<'h, Value<'h>>),
+    StringObj(VecRef<'h, char>),
+}
+
+fn count_shared<'h>(v: &Value<'h>, counts: &mut HashMap<SharedObj<'h>, u32>) {
+    match *v {
+        Cons(ref p) => {
+            let key = SharedObj::Pair(p.clone());
+            let count = counts.entry(key).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                count_shared(&p.car(), counts);
+                count_shared(&p.cdr(), counts);
+            }
+        }
+        Vector(ref vec) => {
+            let key = SharedObj::Vector(vec.clone());
+            let count = counts.entry(key).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                for i in 0..vec.len() {
+                    count_shared(&vec.get(i), counts);
+                }
+            }
+        }
+        StringObj(ref s) => {
+            *counts.entry(SharedObj::StringObj(s.clone())).or_insert(0) += 1;
+        }
+        _ => {}
+    }
+}
+
+struct PrintState<'h> {
+    shared: HashSet<SharedObj<'h>>,
+    labels: HashMap<SharedObj<'h>, u32>,
+    printed: HashSet<SharedObj<'h>>,
+    next_label: u32,
+    /// `write`'s quoted, escaped rendering of strings and characters, versus
+    /// `display`'s raw one.
+    quote: bool,
+}
+
+// Returns `true` if the caller should go on to print `key`'s contents, and
+// `false` if a `#n#` back-reference was emitted instead and the caller
+// should stop recursing.
+fn enter_shared<'h>(
+    f: &mut fmt::Formatter,
+    key: &SharedObj<'h>,
+    state: &mut PrintState<'h>,
+) -> Result<bool, fmt::Error> {
+    if !state.shared.contains(key) {
+        return Ok(true);
+    }
+    if state.printed.contains(key) {
+        let label = state.labels[key];
+        write!(f, "#{}#", label)?;
+        return Ok(false);
+    }
+    let label = state.next_label;
+    state.next_label += 1;
+    state.labels.insert(key.clone(), label);
+    state.printed.insert(key.clone());
+    write!(f, "#{}=", label)?;
+    Ok(true)
+}
+
+fn print_state<'h>(root: &Value<'h>, quote: bool) -> PrintState<'h> {
+    let mut counts = HashMap::new();
+    count_shared(root, &mut counts);
+    let shared = counts
+        .into_iter()
+        .filter(|&(_, n)| n > 1)
+        .map(|(key, _)| key)
+        .collect();
+    PrintState {
+        shared,
+        labels: HashMap::new(),
+        printed: HashSet::new(),
+        next_label: 0,
+        quote,
+    }
+}
+
+/// `display`: the raw rendering used throughout this crate (error messages,
+/// the REPL, etc.) via `{}`. Strings print their contents unquoted and
+/// characters print as themselves.
 impl<'h> fmt::Display for Value<'h> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Note that this will need to add a set of already-printed pairs if we add
-        // `set-car!` and/or `set-cdr!` and introduce the possibility of cycles.
-        match *self {
-            Nil => write!(f, "nil"),
-            Bool(true) => write!(f, "#t"),
-            Bool(false) => write!(f, "#f"),
-            Int(n) => write!(f, "{}", n),
-            Symbol(ref s) => write!(f, "{}", s.as_str()),
-            Lambda(_) => write!(f, "#lambda"),
-            Code(_) => write!(f, "#code"),
-            Builtin(_) => write!(f, "#builtin"),
-            Cons(ref p) => {
-                write!(f, "(")?;
-                write_pair(f, p.clone())?;
-                write!(f, ")")
+        let mut state = print_state(self, false);
+        write_value(f, self, &mut state)
+    }
+}
+
+/// `write`: like `display`, but strings are quoted and escaped and
+/// characters are rendered as `#\x` datums, so the output can be read back
+/// in. Wrap a `Value` in this to get that rendering: `write!(f, "{}",
+/// value::Write(&v))`.
+pub struct Write<'v, 'h: 'v>(pub &'v Value<'h>);
+
+impl<'v, 'h> fmt::Display for Write<'v, 'h> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut state = print_state(self.0, true);
+        write_value(f, self.0, &mut state)
+    }
+}
+
+fn escape_char(c: char) -> String {
+    match c {
+        ' ' => "#\\space".to_string(),
+        '\n' => "#\\newline".to_string(),
+        '\t' => "#\\tab".to_string(),
+        _ => format!("#\\{}", c),
+    }
+}
+
+fn escape_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn write_value<'h>(
+    f: &mut fmt::Formatter,
+    v: &Value<'h>,
+    state: &mut PrintState<'h>,
+) -> fmt::Result {
+    match *v {
+        Nil => write!(f, "nil"),
+        Bool(true) => write!(f, "#t"),
+        Bool(false) => write!(f, "#f"),
+        Int(n) => write!(f, "{}", n),
+        BigInt(ref b) => write!(f, "{}", b.to_string()),
+        Char(c) => {
+            if state.quote {
+                write!(f, "{}", escape_char(c))
+            } else {
+                write!(f, "{}", c)
             }
-            Vector(ref v) => {
-                write!(f, "#(")?;
-                for i in 0..v.len() {
-                    if i != 0 {
-                        write!(f, " ")?;
-                    }
-                    write!(f, "{}", v.get(i))?;
+        }
+        Symbol(ref s) => write!(f, "{}", s.as_str()),
+        Lambda(_) => write!(f, "#lambda"),
+        Code(_) => write!(f, "#code"),
+        Builtin(_) => write!(f, "#builtin"),
+        Cons(ref p) => {
+            let key = SharedObj::Pair(p.clone());
+            if !enter_shared(f, &key, state)? {
+                return Ok(());
+            }
+            write!(f, "(")?;
+            write_pair(f, p.clone(), state)?;
+            write!(f, ")")
+        }
+        Vector(ref v) => {
+            let key = SharedObj::Vector(v.clone());
+            if !enter_shared(f, &key, state)? {
+                return Ok(());
+            }
+            write!(f, "#(")?;
+            for i in 0..v.len() {
+                if i != 0 {
+                    write!(f, " ")?;
                 }
-                write!(f, ")")
+                write_value(f, &v.get(i), state)?;
+            }
+            write!(f, ")")
+        }
+        ImmString(ref s) => {
+            if state.quote {
+                write!(f, "{}", escape_str(&s.to_string()))
+            } else {
+                write!(f, "{}", s.to_string())
+            }
+        }
+        StringObj(ref v) => {
+            let key = SharedObj::StringObj(v.clone());
+            if !enter_shared(f, &key, state)? {
+                return Ok(());
+            }
+            let contents: String = (0..v.len()).map(|i| v.get(i)).collect();
+            if state.quote {
+                write!(f, "{}", escape_str(&contents))
+            } else {
+                write!(f, "{}", contents)
             }
-            Environment(_) => write!(f, "#environment"),
         }
+        Environment(_) => write!(f, "#environment"),
     }
 }
 
-fn write_pair<'h>(f: &mut fmt::Formatter, pair: PairRef<'h>) -> fmt::Result {
-    write!(f, "{}", pair.car())?;
+fn write_pair<'h>(
+    f: &mut fmt::Formatter,
+    pair: PairRef<'h>,
+    state: &mut PrintState<'h>,
+) -> fmt::Result {
+    write_value(f, &pair.car(), state)?;
     match pair.cdr() {
         Nil => Ok(()),
         Cons(p) => {
-            write!(f, " ")?;
-            write_pair(f, p)
+            // If the tail is itself a shared object, it must get its own
+            // `#n=`/`#n#` treatment, so we can't just flatten it into this
+            // list the way an ordinary proper-list tail is flattened.
+            if state.shared.contains(&SharedObj::Pair(p.clone())) {
+                write!(f, " . ")?;
+                write_value(f, &Cons(p), state)
+            } else {
+                write!(f, " ")?;
+                write_pair(f, p, state)
+            }
         }
         otherwise => {
             write!(f, " . ")?;
-            write!(f, "{}", otherwise)
+            write_value(f, &otherwise, state)
         }
     }
 }
@@ -130,6 +333,9 @@ impl<'h> Value<'h> {
     pub fn as_int(self, error_msg: &str) -> Result<i32, String> {
         match self {
             Int(i) => Ok(i),
+            BigInt(b) => b.unwrap()
+                .to_i32()
+                .ok_or_else(|| format!("{}: number too large", error_msg)),
             _ => Err(format!("{}: number required", error_msg)),
         }
     }
@@ -143,10 +349,88 @@ impl<'h> Value<'h> {
                     Err(format!("{}: negative vector index", error_msg))
                 }
             }
+            BigInt(b) => b.unwrap()
+                .to_usize()
+                .ok_or_else(|| format!("{}: vector index out of range", error_msg)),
             _ => Err(format!("{}: vector index required", error_msg)),
         }
     }
 
+    pub fn is_number(&self) -> bool {
+        match *self {
+            Int(_) | BigInt(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Convert a number (fixnum or bignum) into a `BigInt`, for use by the
+    /// arithmetic builtins' slow path.
+    fn as_big_int(self, error_msg: &str) -> Result<BigInt, String> {
+        match self {
+            Int(i) => Ok(BigInt::from(i)),
+            BigInt(b) => Ok(b.unwrap()),
+            _ => Err(format!("{}: number required", error_msg)),
+        }
+    }
+
+    /// Demote a `BigInt` result back to a fixnum `Int` when it fits, so that
+    /// e.g. `(- (+ 2147483647 1) 1)` is a fixnum again instead of forever
+    /// being a bignum.
+    fn from_big_int(n: BigInt) -> Value<'h> {
+        match n.to_i32() {
+            Some(i) => Int(i),
+            None => BigInt(GcLeaf::new(n)),
+        }
+    }
+
+    pub fn checked_add(self, other: Value<'h>, error_msg: &str) -> Result<Value<'h>, String> {
+        if let (Int(a), Int(b)) = (self.clone(), other.clone()) {
+            if let Some(sum) = a.checked_add(b) {
+                return Ok(Int(sum));
+            }
+        }
+        let a = self.as_big_int(error_msg)?;
+        let b = other.as_big_int(error_msg)?;
+        Ok(Value::from_big_int(a + b))
+    }
+
+    pub fn checked_sub(self, other: Value<'h>, error_msg: &str) -> Result<Value<'h>, String> {
+        if let (Int(a), Int(b)) = (self.clone(), other.clone()) {
+            if let Some(diff) = a.checked_sub(b) {
+                return Ok(Int(diff));
+            }
+        }
+        let a = self.as_big_int(error_msg)?;
+        let b = other.as_big_int(error_msg)?;
+        Ok(Value::from_big_int(a - b))
+    }
+
+    pub fn checked_mul(self, other: Value<'h>, error_msg: &str) -> Result<Value<'h>, String> {
+        if let (Int(a), Int(b)) = (self.clone(), other.clone()) {
+            if let Some(product) = a.checked_mul(b) {
+                return Ok(Int(product));
+            }
+        }
+        let a = self.as_big_int(error_msg)?;
+        let b = other.as_big_int(error_msg)?;
+        Ok(Value::from_big_int(a * b))
+    }
+
+    /// Numeric equality (as opposed to `Value`'s derived `PartialEq`, which
+    /// is stricter and tells an `Int` from a `BigInt`): a big-valued and a
+    /// small-valued representation of the same mathematical integer are
+    /// equal, regardless of which arithmetic path produced each one.
+    pub fn num_eq(&self, other: &Value<'h>, error_msg: &str) -> Result<bool, String> {
+        match (self, other) {
+            (&Int(a), &Int(b)) => Ok(a == b),
+            _ => {
+                let a = self.clone().as_big_int(error_msg)?;
+                let b = other.clone().as_big_int(error_msg)?;
+                Ok(a == b)
+            }
+        }
+    }
+
     pub fn is_pair(&self) -> bool {
         match *self {
             Cons(_) => true,
@@ -189,6 +473,46 @@ impl<'h> Value<'h> {
             _ => false,
         }
     }
+
+    pub fn is_char(&self) -> bool {
+        match *self {
+            Char(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_char(self, error_msg: &str) -> Result<char, String> {
+        match self {
+            Char(c) => Ok(c),
+            _ => Err(format!("{}: character required", error_msg)),
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        match *self {
+            ImmString(_) | StringObj(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Collect a string value's contents (either representation) into an
+    /// owned `String`, for builtins that just need to read them.
+    pub fn as_string(self, error_msg: &str) -> Result<String, String> {
+        match self {
+            ImmString(s) => Ok(s.unwrap().to_string()),
+            StringObj(v) => Ok((0..v.len()).map(|i| v.get(i)).collect()),
+            _ => Err(format!("{}: string required", error_msg)),
+        }
+    }
+
+    /// The mutable, GC-allocated buffer backing a `StringObj`, for builtins
+    /// like `string-set!` that need to mutate it in place.
+    pub fn as_string_obj(self, error_msg: &str) -> Result<VecRef<'h, char>, String> {
+        match self {
+            StringObj(v) => Ok(v),
+            _ => Err(format!("{}: mutable string required", error_msg)),
+        }
+    }
 }
 
 impl<'h> Iterator for Value<'h> {
@@ -206,45 +530,109 @@ impl<'h> Iterator for Value<'h> {
 }
 
 
-#[derive(Clone, Debug)]
-pub struct InternedString(Arc<String>);
+/// A symbol, represented as a small dense index into the global [`AtomTable`].
+/// Equality and hashing are just an integer compare/hash; the actual text
+/// lives in the table and is only looked up on demand (e.g. for `as_str` or
+/// `Display`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct InternedString(u32);
 
-// Note: If we ever impl Hash for InternedString, it will be better to use a
-// custom pointer-based implementation than to use derive(Hash), which would
-// hash the contents of the string.
-impl PartialEq for InternedString {
-    fn eq(&self, other: &InternedString) -> bool {
-        Arc::ptr_eq(&self.0, &other.0)
-    }
+struct AtomTable {
+    // Indexed by `InternedString`'s `u32`. Strings are never removed, so an
+    // index, once assigned, is valid (and stable) forever.
+    atoms: Vec<Arc<str>>,
+    by_value: HashMap<Arc<str>, u32>,
 }
 
-impl Eq for InternedString {}
+impl AtomTable {
+    fn new() -> AtomTable {
+        AtomTable {
+            atoms: vec![],
+            by_value: HashMap::new(),
+        }
+    }
 
-lazy_static! {
-    static ref STRINGS: Mutex<HashSet<InternedStringByValue>> = Mutex::new(HashSet::new());
-}
+    fn intern(&mut self, s: &str) -> u32 {
+        if let Some(&i) = self.by_value.get(s) {
+            return i;
+        }
+        let s: Arc<str> = Arc::from(s);
+        let i = self.atoms.len() as u32;
+        self.atoms.push(s.clone());
+        self.by_value.insert(s, i);
+        i
+    }
 
-#[derive(Eq, Hash, PartialEq)]
-struct InternedStringByValue(Arc<String>);
+    // Adds a new atom without interning it by value, so it can never be
+    // returned by `intern` and alias a user-written symbol of the same
+    // spelling. Used for `gensym`.
+    fn add_uninterned(&mut self, s: String) -> u32 {
+        let i = self.atoms.len() as u32;
+        self.atoms.push(Arc::from(s));
+        i
+    }
 
-impl Borrow<str> for InternedStringByValue {
-    fn borrow(&self) -> &str {
-        &self.0
+    fn text(&self, index: u32) -> Arc<str> {
+        self.atoms[index as usize].clone()
     }
 }
 
+// NOT IMPLEMENTED: `atoms`/`by_value` only ever grow, so an atom interned
+// once is never reclaimed even after the last `Value::Symbol` naming it is
+// gone. A real fix needs a `WeakRef<'h, T>` companion to `GcRef` -- one the
+// collector does not trace, and that `upgrade()`s to `None` once `force_gc`
+// has swept its target -- so this table could key on weak handles (or a
+// weak hash table built on the same primitive) and prune entries whose
+// symbol has died on each sweep. That primitive would have to live in the
+// `cell_gc` crate itself, and no `cell_gc` source is checked into this
+// tree (only the `lisp` crate that consumes it is), so it cannot be added
+// here. This table leaks every interned atom for the life of the process
+// until `cell_gc` grows that primitive; nothing below works around it.
+lazy_static! {
+    static ref ATOMS: Mutex<AtomTable> = Mutex::new(AtomTable::new());
+}
+
 impl InternedString {
     pub fn get(s: &str) -> InternedString {
-        let mut guard = STRINGS.lock().unwrap();
-        if let Some(x) = guard.get(s) {
-            return InternedString(x.0.clone());
-        }
-        let s = Arc::new(s.to_string());
-        guard.insert(InternedStringByValue(s.clone()));
-        InternedString(s)
+        InternedString(ATOMS.lock().unwrap().intern(s))
+    }
+
+    /// Create a fresh symbol, distinct from every symbol interned by `get`
+    /// (even one that happens to print the same way), for use by hygienic
+    /// macro expansion.
+    pub fn gensym() -> InternedString {
+        let mut table = ATOMS.lock().unwrap();
+        let name = format!("%gensym-{}", table.atoms.len());
+        InternedString(table.add_uninterned(name))
+    }
+
+    /// The stable integer identifying this symbol in the atom table, for
+    /// code (e.g. compiled `Code`) that wants to refer to symbols by a
+    /// plain integer rather than carrying this type around.
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+
+    pub fn from_index(index: u32) -> InternedString {
+        InternedString(index)
+    }
+
+    pub fn as_str(&self) -> String {
+        ATOMS.lock().unwrap().text(self.0).to_string()
+    }
+
+    /// Run `f` against a borrow of this symbol's text, without allocating
+    /// a fresh `String` the way `as_str` does. Prefer this on hot paths
+    /// like `compile_expr`'s keyword dispatch, which re-checks a symbol's
+    /// spelling against many candidates per compiled form.
+    pub fn with_str<R, F: FnOnce(&str) -> R>(&self, f: F) -> R {
+        let text = ATOMS.lock().unwrap().text(self.0);
+        f(&text)
     }
 
-    pub fn as_str(&self) -> &str {
-        &self.0
+    /// Shorthand for the `with_str(|s| s == text)` comparison that makes up
+    /// most of the compiler's keyword dispatch.
+    pub fn is(&self, text: &str) -> bool {
+        self.with_str(|s| s == text)
     }
 }
\ No newline at end of file
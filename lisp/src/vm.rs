@@ -4,6 +4,7 @@ use builtins;
 use cell_gc::{GcHeapSession, GcLeaf};
 use cell_gc::collections::VecRef;
 use compile::{self, Expr};
+use macros;
 use parse;
 use value::{InternedString, Pair, Value};
 use value::Value::*;
@@ -23,8 +24,34 @@ pub enum Trampoline<'h> {
 impl<'h> Trampoline<'h> {
     /// Complete the evaluation of this value. Avoids recursion to implement
     /// proper tail calls and keep from blowing the stack.
-    pub fn eval(mut self, hs: &mut GcHeapSession<'h>) -> Result<Value<'h>, String> {
+    pub fn eval(self, hs: &mut GcHeapSession<'h>) -> Result<Value<'h>, String> {
+        self.eval_traced(hs, &mut |_| {})
+    }
+
+    /// Like `eval`, but calls `trace` with each `TailCall` just before it is
+    /// applied, so a caller such as the stage-introspecting REPL (see
+    /// `language`/`repl`) can watch the trampoline bounce as the stack
+    /// unwinds.
+    pub fn eval_traced(
+        mut self,
+        hs: &mut GcHeapSession<'h>,
+        trace: &mut FnMut(&Trampoline<'h>),
+    ) -> Result<Value<'h>, String> {
         while let Trampoline::TailCall { func, args } = self {
+            trace(&Trampoline::TailCall {
+                func: func.clone(),
+                args: args.clone(),
+            });
+            // Every call in CPS form passes its continuation as the last
+            // argument, but a builtin takes only its own arguments: run it
+            // on those and hand its result on to the continuation.
+            if let Builtin(ref f) = func {
+                let mut args = args;
+                let k = args.pop().ok_or_else(|| "apply: continuation required".to_string())?;
+                let value = (f.0)(hs, args)?.eval(hs)?;
+                self = Trampoline::TailCall { func: k, args: vec![value] };
+                continue;
+            }
             self = apply(hs, func, args)?;
         }
         match self {
@@ -177,7 +204,7 @@ pub fn apply<'h>(
 /// Evaluate `expr` until we reach a tail call, at which point it is packaged up
 /// as a `Trampoline::TailCall` and returned so we can unwind the stack before
 /// continuing evaluation.
-fn eval_to_tail_call<'h>(
+pub(crate) fn eval_to_tail_call<'h>(
     hs: &mut GcHeapSession<'h>,
     expr: Expr<'h>,
     env: EnvironmentRef<'h>,
@@ -265,7 +292,9 @@ pub fn eval<'h>(
     expr: Value<'h>,
     env: EnvironmentRef<'h>,
 ) -> Result<Value<'h>, String> {
-    let expr = compile::compile_toplevel(hs, expr)?;
+    let mut menv = macros::MacroEnv::new();
+    let expr = compile::compile_toplevel(hs, &mut menv, expr)?;
+    let expr = compile::cps_toplevel(hs, expr);
     eval_compiled(hs, expr, env)
 }
 